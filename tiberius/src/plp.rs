@@ -1,8 +1,13 @@
 //! Partially Length-Prefixed types handling
 
-use byteorder::{LittleEndian, ReadBytesExt};
+use std::cmp;
+use std::mem;
+
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
+use encoding::{DecoderTrap, EncodingRef};
 use futures::{Async, Poll};
 
+use collation::Collation;
 use Error;
 
 /// Mode for type reader.
@@ -25,71 +30,640 @@ impl ReadTyMode {
     }
 }
 
+/// NULL value sentinel for the initial (Plp) size prefix.
+const PLP_NULL: u64 = 0xffffffffffffffff;
+
+/// Ceiling applied to the total number of bytes a `ReadTyState` will
+/// allocate for a single value unless overridden with
+/// `ReadTyState::with_max_allocation`. Chosen to comfortably fit the large
+/// LOB values tiberius is expected to transfer while still refusing to
+/// honor a clearly bogus wire-declared length.
+const DEFAULT_MAX_ALLOCATION: usize = 2 * 1024 * 1024 * 1024; // 2 GiB
+
+/// Largest number of bytes grown into `chunk_buf` at a time. The wire
+/// declares a chunk's full length up front, but we only ever grow towards
+/// it in steps this size, so a hostile chunk-length header can't force a
+/// multi-gigabyte allocation before any payload bytes have actually arrived.
+const READ_STEP: usize = 64 * 1024;
+
+/// A fixed-size header field (the initial size/NULL prefix, or a PLP
+/// chunk-length prefix) that may only be partially available in the input,
+/// e.g. because a TDS packet boundary falls in the middle of it. Bytes are
+/// accumulated across polls until `needed` of them have arrived.
+#[derive(Debug)]
+struct PartialHeader {
+    buf: [u8; 8],
+    filled: usize,
+    needed: usize,
+}
+
+impl PartialHeader {
+    fn new(needed: usize) -> Self {
+        PartialHeader {
+            buf: [0; 8],
+            filled: 0,
+            needed,
+        }
+    }
+
+    /// Try to read the remaining bytes of this header from `input`.
+    fn poll_fill(&mut self, input: &mut impl ReadBytesExt) -> Poll<(), Error> {
+        while self.filled < self.needed {
+            let read = input.read(&mut self.buf[self.filled..self.needed])?;
+            if read == 0 {
+                return Ok(Async::NotReady);
+            }
+            self.filled += read;
+        }
+        Ok(Async::Ready(()))
+    }
+
+    fn as_u16(&self) -> u16 {
+        LittleEndian::read_u16(&self.buf[..2])
+    }
+
+    fn as_u32(&self) -> u32 {
+        LittleEndian::read_u32(&self.buf[..4])
+    }
+
+    fn as_u64(&self) -> u64 {
+        LittleEndian::read_u64(&self.buf[..8])
+    }
+}
+
+/// State machine driving `ReadTyState::read_chunk`.
+#[derive(Debug)]
+enum State {
+    /// Reading the initial size/NULL prefix (2 bytes for `FixedSize`, 8 for
+    /// `Plp`).
+    Size(PartialHeader),
+    /// Reading the next PLP chunk-length prefix (4 bytes). Not used in
+    /// `FixedSize` mode, where the whole value is a single chunk.
+    ChunkLen(PartialHeader),
+    /// Copying the data of the current chunk.
+    Data,
+    /// The value has been fully read (NULL, the zero-length sentinel was
+    /// found, or the single `FixedSize` chunk was read).
+    Done,
+}
+
 /// A partially read type
 #[derive(Debug)]
 pub struct ReadTyState {
     mode: ReadTyMode,
-    data: Option<Vec<u8>>,
+    state: State,
+    is_null: bool,
     chunk_data_left: usize,
+    /// Bytes read so far for the chunk currently in progress (when driven by
+    /// `read`, preceded by the value's earlier chunks).
+    chunk_buf: Vec<u8>,
+    /// Maximum number of bytes this reader will allocate for the value.
+    max_allocation: usize,
+    /// Bytes declared/accumulated so far, checked against `max_allocation`.
+    total_size: usize,
 }
 
 impl ReadTyState {
     /// Initialize a type reader
     pub fn new(mode: ReadTyMode) -> Self {
+        let size_len = match mode {
+            ReadTyMode::FixedSize(_) => 2,
+            ReadTyMode::Plp => 8,
+        };
+
         ReadTyState {
             mode,
-            data: None,
+            state: State::Size(PartialHeader::new(size_len)),
+            is_null: false,
             chunk_data_left: 0,
+            chunk_buf: Vec::new(),
+            max_allocation: DEFAULT_MAX_ALLOCATION,
+            total_size: 0,
         }
     }
 
-    /// Read data stream as Plain or PLP
+    /// Override the maximum number of bytes this reader will allocate for a
+    /// single value.
     ///
-    /// Returns bytes read or `None` if the value turned out to be NULL
-    pub fn read(&mut self, input: &mut impl ReadBytesExt) -> Poll<Option<Vec<u8>>, Error> {
-        // If we did not read anything yet, initialize the reader.
-        if self.data.is_none() {
-            let size = match self.mode {
-                ReadTyMode::FixedSize(_) => input.read_u16::<LittleEndian>()? as u64,
-                ReadTyMode::Plp => input.read_u64::<LittleEndian>()?,
-            };
-
-            self.data = match size {
-                0xffffffffffffffff => None, // NULL value
-                0xfffffffffffffffe => Some(Vec::new()), // unknown size
-                len => Some(Vec::with_capacity(len as usize)), // given size
-            };
-
-            // If this is not PLP, treat everything as a single chunk.
-            if let ReadTyMode::FixedSize(_) = self.mode {
-                self.chunk_data_left = size as usize;
-            }
+    /// Without this guard, a malformed or hostile TDS stream could declare
+    /// an enormous chunk or value length and trigger an OOM before a single
+    /// byte of real data arrives; exceeding the limit is reported as an
+    /// `Error` instead.
+    pub fn with_max_allocation(mut self, max_allocation: usize) -> Self {
+        self.max_allocation = max_allocation;
+        self
+    }
+
+    /// Account for `additional` more bytes being allocated for the value,
+    /// failing if that pushes the running total past `max_allocation`.
+    fn check_allocation(&mut self, additional: usize) -> Result<(), Error> {
+        self.total_size = self.total_size.saturating_add(additional);
+        if self.total_size > self.max_allocation {
+            return Err(Error::Protocol(
+                format!(
+                    "value size of {} bytes exceeds the configured maximum allocation of {} bytes",
+                    self.total_size, self.max_allocation
+                ).into(),
+            ));
         }
+        Ok(())
+    }
 
-        // If there is a buffer, we have something to read.
-        if let Some(ref mut buf) = self.data {
-            loop {
-                if self.chunk_data_left == 0 {
-                    // We have no chunk. Start a new one.
-                    let chunk_size = match self.mode {
-                        ReadTyMode::FixedSize(_) => 0,
-                        ReadTyMode::Plp => input.read_u32::<LittleEndian>()? as usize,
+    /// Read the next chunk of the value, streaming it as soon as it has been
+    /// fully read instead of accumulating the whole value in memory.
+    ///
+    /// Returns `Some(chunk)` for every chunk of a non-NULL value; returns
+    /// `None` once the value has ended (the zero-length sentinel in `Plp`
+    /// mode, or after the single chunk in `FixedSize` mode), which also
+    /// covers the NULL case (a NULL value never produces a chunk). Callers
+    /// that want the whole value at once can keep calling this until it
+    /// returns `None` and concatenate the chunks; see `read`.
+    ///
+    /// Every step, including the initial size prefix and each PLP
+    /// chunk-length prefix, is resumable: if `input` doesn't yet hold enough
+    /// bytes for the header or chunk currently in progress, this returns
+    /// `Async::NotReady` and picks up exactly where it left off on the next
+    /// call.
+    pub fn read_chunk(&mut self, input: &mut impl ReadBytesExt) -> Poll<Option<Vec<u8>>, Error> {
+        loop {
+            match mem::replace(&mut self.state, State::Done) {
+                State::Size(mut header) => {
+                    if let Async::NotReady = header.poll_fill(input)? {
+                        self.state = State::Size(header);
+                        return Ok(Async::NotReady);
+                    }
+
+                    let size = match self.mode {
+                        ReadTyMode::FixedSize(_) => u64::from(header.as_u16()),
+                        ReadTyMode::Plp => header.as_u64(),
                     };
-                    if chunk_size == 0 {
-                        break // found a sentinel, we're done
+
+                    self.is_null = size == PLP_NULL;
+
+                    self.state = if self.is_null {
+                        State::Done
+                    } else if let ReadTyMode::FixedSize(_) = self.mode {
+                        // If this is not PLP, treat everything as a single chunk.
+                        self.check_allocation(size as usize)?;
+                        self.chunk_data_left = size as usize;
+                        State::Data
                     } else {
-                        self.chunk_data_left = chunk_size
+                        State::ChunkLen(PartialHeader::new(4))
+                    };
+                }
+                State::ChunkLen(mut header) => {
+                    if let Async::NotReady = header.poll_fill(input)? {
+                        self.state = State::ChunkLen(header);
+                        return Ok(Async::NotReady);
                     }
+
+                    let chunk_size = header.as_u32() as usize;
+                    self.state = if chunk_size == 0 {
+                        State::Done // found a sentinel, we're done
+                    } else {
+                        self.check_allocation(chunk_size)?;
+                        self.chunk_data_left = chunk_size;
+                        self.chunk_buf.reserve(cmp::min(chunk_size, READ_STEP));
+                        State::Data
+                    };
+                }
+                State::Data => {
+                    // Read the remaining chunk in bounded steps instead of
+                    // one byte (or one multi-gigabyte allocation) at a time:
+                    // each iteration only grows the buffer by up to
+                    // `READ_STEP`, however large the chunk's declared length
+                    // is, so real bytes arrive before we ever allocate for
+                    // the whole thing.
+                    while self.chunk_data_left > 0 {
+                        let old_len = self.chunk_buf.len();
+                        let step = cmp::min(self.chunk_data_left, READ_STEP);
+                        self.chunk_buf.resize(old_len + step, 0);
+                        let read = input.read(&mut self.chunk_buf[old_len..])?;
+                        self.chunk_buf.truncate(old_len + read);
+                        self.chunk_data_left -= read;
+
+                        if read == 0 {
+                            self.state = State::Data;
+                            return Ok(Async::NotReady);
+                        }
+                    }
+
+                    self.state = match self.mode {
+                        ReadTyMode::FixedSize(_) => State::Done,
+                        ReadTyMode::Plp => State::ChunkLen(PartialHeader::new(4)),
+                    };
+
+                    return Ok(Async::Ready(Some(mem::take(&mut self.chunk_buf))));
+                }
+                State::Done => {
+                    self.state = State::Done;
+                    return Ok(Async::Ready(None));
+                }
+            }
+        }
+    }
+
+    /// Read data stream as Plain or PLP, buffering every chunk into a single
+    /// value.
+    ///
+    /// Returns bytes read or `None` if the value turned out to be NULL. For
+    /// large LOB values, prefer `read_chunk` so the whole value never has to
+    /// live in memory at once.
+    pub fn read(&mut self, input: &mut impl ReadBytesExt) -> Poll<Option<Vec<u8>>, Error> {
+        loop {
+            match self.read_chunk(input)? {
+                // Keep the value read so far in `chunk_buf`: `read_chunk`
+                // appends the next chunk to it, and it survives a `NotReady`.
+                Async::Ready(Some(data)) => self.chunk_buf = data,
+                Async::Ready(None) => break,
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+
+        if self.is_null {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::Ready(Some(mem::take(&mut self.chunk_buf))))
+        }
+    }
+}
+
+/// Target representation to decode an assembled PLP/fixed-size value into.
+#[derive(Debug, Clone, Copy)]
+pub enum PlpTypeInfo {
+    /// Pass bytes through unchanged, e.g. VARBINARY(MAX).
+    Bytes,
+    /// UTF-16LE text, e.g. NVARCHAR(MAX).
+    Utf16,
+    /// Text in the codepage of the column's collation (single- or
+    /// double-byte), e.g. VARCHAR(MAX).
+    Codepage(Collation),
+}
+
+/// A decoded chunk of a value, as produced by `TypedReadTyState::read_chunk`.
+#[derive(Debug)]
+pub enum PlpChunk {
+    /// Raw bytes, for `PlpTypeInfo::Bytes`.
+    Bytes(Vec<u8>),
+    /// Text that has already been decoded to UTF-8.
+    Text(String),
+}
+
+/// Wraps a `ReadTyState`, decoding each assembled chunk into its column's
+/// target representation as it arrives, instead of leaving every caller to
+/// re-parse the raw bytes according to the type/collation.
+///
+/// A multi-byte character that straddles a chunk boundary is carried over
+/// into the next chunk rather than being dropped or causing a decode error.
+#[derive(Debug)]
+pub struct TypedReadTyState {
+    inner: ReadTyState,
+    info: PlpTypeInfo,
+    /// Trailing bytes of the previous chunk that did not yet decode into a
+    /// complete character (a lone UTF-16 surrogate half, or the lead byte(s)
+    /// of a split DBCS codepage sequence).
+    carry: Vec<u8>,
+}
+
+impl TypedReadTyState {
+    /// Initialize a typed type reader
+    pub fn new(mode: ReadTyMode, info: PlpTypeInfo) -> Self {
+        TypedReadTyState {
+            inner: ReadTyState::new(mode),
+            info,
+            carry: Vec::new(),
+        }
+    }
+
+    /// See `ReadTyState::with_max_allocation`.
+    pub fn with_max_allocation(mut self, max_allocation: usize) -> Self {
+        self.inner = self.inner.with_max_allocation(max_allocation);
+        self
+    }
+
+    /// Read and decode the next chunk of the value. See
+    /// `ReadTyState::read_chunk` for the chunk/NULL semantics.
+    pub fn read_chunk(&mut self, input: &mut impl ReadBytesExt) -> Poll<Option<PlpChunk>, Error> {
+        let chunk = match self.inner.read_chunk(input)? {
+            Async::Ready(Some(chunk)) => chunk,
+            Async::Ready(None) => {
+                if !self.carry.is_empty() {
+                    return Err(Error::Protocol(
+                        "value ended with a truncated multi-byte character".into(),
+                    ));
+                }
+                return Ok(Async::Ready(None));
+            }
+            Async::NotReady => return Ok(Async::NotReady),
+        };
+
+        match self.info {
+            PlpTypeInfo::Bytes => Ok(Async::Ready(Some(PlpChunk::Bytes(chunk)))),
+            PlpTypeInfo::Utf16 => {
+                let mut bytes = mem::take(&mut self.carry);
+                bytes.extend(chunk);
+
+                // An odd trailing byte is half of a UTF-16 code unit; carry
+                // it over instead of decoding it now.
+                let mut carry = if bytes.len() % 2 != 0 {
+                    vec![bytes.pop().unwrap()]
                 } else {
-                    // Just read a byte
-                    let byte = input.read_u8()?;
-                    self.chunk_data_left -= 1;
-                    buf.push(byte);
+                    Vec::new()
+                };
+
+                let mut units: Vec<u16> = bytes.chunks(2).map(LittleEndian::read_u16).collect();
+
+                // A trailing high surrogate starts a pair that's only
+                // completed by the next chunk; carry its 2 bytes over
+                // rather than decoding a lone surrogate now.
+                if let Some(&last) = units.last() {
+                    if (0xd800..=0xdbff).contains(&last) {
+                        units.pop();
+                        let mut surrogate = vec![last as u8, (last >> 8) as u8];
+                        surrogate.extend(carry);
+                        carry = surrogate;
+                    }
+                }
+                self.carry = carry;
+
+                let text = String::from_utf16(&units).map_err(|_| {
+                    Error::Protocol("invalid UTF-16 data in NVARCHAR(MAX) value".into())
+                })?;
+
+                Ok(Async::Ready(Some(PlpChunk::Text(text))))
+            }
+            PlpTypeInfo::Codepage(collation) => {
+                let encoding = collation
+                    .encoding()
+                    .ok_or_else(|| Error::Protocol("unsupported column collation".into()))?;
+
+                let mut bytes = mem::take(&mut self.carry);
+                bytes.extend(chunk);
+
+                let (text, carry) = decode_codepage_prefix(encoding, &bytes).map_err(|_| {
+                    Error::Protocol("invalid text data in VARCHAR(MAX) value".into())
+                })?;
+                self.carry = carry;
+
+                Ok(Async::Ready(Some(PlpChunk::Text(text))))
+            }
+        }
+    }
+}
+
+/// Decode the longest prefix of `bytes` that forms complete characters under
+/// `encoding`, returning the decoded text together with any trailing bytes
+/// that did not yet form a complete character.
+///
+/// A DBCS codepage can have a character's trailing byte(s) land in the next
+/// PLP chunk. There's no incremental decoder API here, so this finds the
+/// longest prefix that decodes cleanly and hands back the rest to be
+/// retried once more bytes have arrived; codepages supported by `encoding`
+/// use at most a couple of bytes per character, so this converges quickly.
+fn decode_codepage_prefix(encoding: EncodingRef, bytes: &[u8]) -> Result<(String, Vec<u8>), ()> {
+    let min_split = bytes.len().saturating_sub(3);
+    let mut split = bytes.len();
+    loop {
+        match encoding.decode(&bytes[..split], DecoderTrap::Strict) {
+            Ok(text) => return Ok((text, bytes[split..].to_vec())),
+            Err(_) if split > min_split => split -= 1,
+            Err(_) => return Err(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp;
+    use std::io::{self, Cursor};
+
+    use byteorder::WriteBytesExt;
+
+    use super::*;
+
+    /// A reader over a fixed byte buffer that panics if asked for more than
+    /// `READ_STEP` bytes in one call, so tests can assert that `ReadTyState`
+    /// never tries to grow its buffer past that step in one go.
+    struct StepLimitedReader {
+        data: Vec<u8>,
+        pos: usize,
+    }
+
+    /// A reader that only exposes bytes explicitly handed to it via `feed`,
+    /// returning `Ok(0)` (not an error) once the caller catches up to what
+    /// has been fed so far — mirrors how a TDS packet boundary can leave the
+    /// transport's buffer exhausted mid-value.
+    struct FeedReader {
+        data: Vec<u8>,
+        pos: usize,
+    }
+
+    impl FeedReader {
+        fn new() -> Self {
+            FeedReader {
+                data: Vec::new(),
+                pos: 0,
+            }
+        }
+
+        fn feed(&mut self, bytes: &[u8]) {
+            self.data.extend_from_slice(bytes);
+        }
+    }
+
+    impl io::Read for FeedReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let remaining = &self.data[self.pos..];
+            let n = cmp::min(buf.len(), remaining.len());
+            buf[..n].copy_from_slice(&remaining[..n]);
+            self.pos += n;
+            Ok(n)
+        }
+    }
+
+    impl io::Read for StepLimitedReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            assert!(
+                buf.len() <= READ_STEP,
+                "requested {} bytes at once, more than READ_STEP ({})",
+                buf.len(),
+                READ_STEP
+            );
+            let remaining = &self.data[self.pos..];
+            let n = cmp::min(buf.len(), remaining.len());
+            buf[..n].copy_from_slice(&remaining[..n]);
+            self.pos += n;
+            Ok(n)
+        }
+    }
+
+    /// Build the wire bytes for a PLP value: the 8-byte total-size hint,
+    /// followed by each chunk prefixed with its 4-byte length, followed by
+    /// the zero-length sentinel.
+    fn plp_bytes(total_len_hint: u64, chunks: &[&[u8]]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.write_u64::<LittleEndian>(total_len_hint).unwrap();
+        for chunk in chunks {
+            buf.write_u32::<LittleEndian>(chunk.len() as u32).unwrap();
+            buf.extend_from_slice(chunk);
+        }
+        buf.write_u32::<LittleEndian>(0).unwrap();
+        buf
+    }
+
+    fn expect_ready(poll: Poll<Option<Vec<u8>>, Error>) -> Option<Vec<u8>> {
+        match poll.unwrap() {
+            Async::Ready(data) => data,
+            Async::NotReady => panic!("expected Async::Ready, got NotReady"),
+        }
+    }
+
+    fn expect_ready_typed(poll: Poll<Option<PlpChunk>, Error>) -> Option<PlpChunk> {
+        match poll.unwrap() {
+            Async::Ready(data) => data,
+            Async::NotReady => panic!("expected Async::Ready, got NotReady"),
+        }
+    }
+
+    #[test]
+    fn read_fixed_size_value() {
+        let mut input = Cursor::new(vec![3, 0, b'a', b'b', b'c']);
+        let mut state = ReadTyState::new(ReadTyMode::FixedSize(0xffff));
+        assert_eq!(expect_ready(state.read(&mut input)), Some(b"abc".to_vec()));
+    }
+
+    #[test]
+    fn read_plp_value_across_multiple_chunks() {
+        let bytes = plp_bytes(0xfffffffffffffffe, &[b"hello, ", b"world"]);
+        let mut input = Cursor::new(bytes);
+        let mut state = ReadTyState::new(ReadTyMode::Plp);
+        assert_eq!(
+            expect_ready(state.read(&mut input)),
+            Some(b"hello, world".to_vec())
+        );
+    }
+
+    #[test]
+    fn read_chunk_streams_each_plp_chunk_separately() {
+        let bytes = plp_bytes(0xfffffffffffffffe, &[b"hello, ", b"world"]);
+        let mut input = Cursor::new(bytes);
+        let mut state = ReadTyState::new(ReadTyMode::Plp);
+
+        assert_eq!(
+            expect_ready(state.read_chunk(&mut input)),
+            Some(b"hello, ".to_vec())
+        );
+        assert_eq!(
+            expect_ready(state.read_chunk(&mut input)),
+            Some(b"world".to_vec())
+        );
+        assert_eq!(expect_ready(state.read_chunk(&mut input)), None);
+    }
+
+    #[test]
+    fn read_chunk_reports_null_without_yielding_a_chunk() {
+        let mut input = Cursor::new(vec![0xff; 8]); // PLP NULL sentinel
+        let mut state = ReadTyState::new(ReadTyMode::Plp);
+        assert_eq!(expect_ready(state.read_chunk(&mut input)), None);
+    }
+
+    #[test]
+    fn read_chunk_rejects_chunk_exceeding_max_allocation() {
+        let mut wire = Vec::new();
+        wire.write_u64::<LittleEndian>(0xfffffffffffffffe).unwrap();
+        wire.write_u32::<LittleEndian>(100).unwrap();
+        let mut input = Cursor::new(wire);
+
+        let mut state = ReadTyState::new(ReadTyMode::Plp).with_max_allocation(10);
+        assert!(state.read_chunk(&mut input).is_err());
+    }
+
+    #[test]
+    fn read_chunk_grows_buffer_in_bounded_steps() {
+        let declared_len = READ_STEP * 3 + 10;
+
+        let mut wire = Vec::new();
+        wire.write_u64::<LittleEndian>(0xfffffffffffffffe).unwrap();
+        wire.write_u32::<LittleEndian>(declared_len as u32).unwrap();
+        wire.extend(vec![0x42u8; declared_len]);
+        wire.write_u32::<LittleEndian>(0).unwrap();
+
+        let mut input = StepLimitedReader { data: wire, pos: 0 };
+        let mut state = ReadTyState::new(ReadTyMode::Plp).with_max_allocation(declared_len + 1);
+
+        // `StepLimitedReader::read` asserts internally that we never ask for
+        // more than `READ_STEP` bytes in one call, however large the
+        // declared chunk length is.
+        assert_eq!(
+            expect_ready(state.read(&mut input)),
+            Some(vec![0x42u8; declared_len])
+        );
+    }
+
+    #[test]
+    fn read_chunk_resumes_when_fed_one_byte_at_a_time() {
+        let wire = plp_bytes(0xfffffffffffffffe, &[b"ab"]);
+        let mut remaining = wire.into_iter();
+        let mut input = FeedReader::new();
+        let mut state = ReadTyState::new(ReadTyMode::Plp);
+
+        let mut chunks = Vec::new();
+        loop {
+            match state.read_chunk(&mut input).unwrap() {
+                Async::Ready(Some(chunk)) => chunks.push(chunk),
+                Async::Ready(None) => break,
+                Async::NotReady => {
+                    let byte = remaining
+                        .next()
+                        .expect("ran out of input while still NotReady");
+                    input.feed(&[byte]);
                 }
             }
         }
 
-        // If we're here, we're done reading.
-        Ok(Async::Ready(self.data.take()))
+        assert_eq!(chunks, vec![b"ab".to_vec()]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn typed_utf16_reassembles_a_surrogate_pair_split_across_chunks() {
+        // U+1F600 as a UTF-16 surrogate pair, split so the first PLP chunk
+        // contains only the high surrogate and the second only the low one.
+        let high: u16 = 0xd83d;
+        let low: u16 = 0xde00;
+        let first_chunk = vec![high as u8, (high >> 8) as u8];
+        let second_chunk = vec![low as u8, (low >> 8) as u8];
+
+        let wire = plp_bytes(0xfffffffffffffffe, &[&first_chunk, &second_chunk]);
+        let mut input = Cursor::new(wire);
+        let mut state = TypedReadTyState::new(ReadTyMode::Plp, PlpTypeInfo::Utf16);
+
+        match expect_ready_typed(state.read_chunk(&mut input)) {
+            Some(PlpChunk::Text(text)) => assert_eq!(text, ""),
+            other => panic!("unexpected {:?}", other),
+        }
+        match expect_ready_typed(state.read_chunk(&mut input)) {
+            Some(PlpChunk::Text(text)) => assert_eq!(text, "\u{1f600}"),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(expect_ready_typed(state.read_chunk(&mut input)).is_none());
+    }
+
+    #[test]
+    fn decode_codepage_prefix_carries_split_dbcs_character() {
+        let gbk = encoding::all::GBK;
+        let full = [0xd6u8, 0xd0u8]; // "中" in GBK
+
+        // Only the lead byte has arrived: nothing decodes yet, it's all
+        // carried over.
+        let (text, carry) = decode_codepage_prefix(gbk, &full[..1]).unwrap();
+        assert_eq!(text, "");
+        assert_eq!(carry, vec![0xd6]);
+
+        // The trailing byte completes the character.
+        let mut next = carry;
+        next.extend_from_slice(&full[1..]);
+        let (text, carry) = decode_codepage_prefix(gbk, &next).unwrap();
+        assert_eq!(text, "\u{4e2d}");
+        assert!(carry.is_empty());
+    }
+}